@@ -0,0 +1,189 @@
+use futures::channel::mpsc;
+use futures::io::AsyncRead;
+use futures::sink::SinkExt;
+use futures::stream::StreamExt;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::packet::*;
+use crate::stream::{self, PacketStream};
+use snafu::Snafu;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Underlying packet stream failed: {}", source))]
+    Stream { source: stream::Error },
+
+    #[snafu(display("PacketStream ended while requests were still open"))]
+    Gone {},
+}
+
+/// Buffer size passed to `mpsc::channel` for each per-request channel. With
+/// its one sender, such a channel holds `CHANNEL_BUFFER + 1` unconsumed
+/// packets before `Demux::run` starts applying backpressure to the
+/// underlying stream.
+const CHANNEL_BUFFER: usize = 16;
+
+/// Demultiplexes a single [`PacketStream`] into per-request-id substreams.
+///
+/// The packet-stream protocol correlates a reply to the request that
+/// opened it via the 4-byte `id` in its header (replies use the negated
+/// id), and a run of packets sharing an id forms a logical channel that is
+/// terminated by a packet with its end/error flag set. `Demux` drives the
+/// underlying `PacketStream` and routes each packet it yields to the
+/// channel registered for its id, so callers no longer have to match
+/// packets to requests by hand.
+pub struct Demux<R> {
+    inner: PacketStream<R>,
+    channels: HashMap<i32, mpsc::Sender<Result<Packet, Arc<Error>>>>,
+    next_id: i32,
+}
+
+impl<R> Demux<R> {
+    pub fn new(r: R) -> Demux<R> {
+        Demux {
+            inner: PacketStream::new(r),
+            channels: HashMap::new(),
+            next_id: 1,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin + 'static> Demux<R> {
+    /// Allocate a fresh outgoing request id and register a channel for its
+    /// replies, returning the id to send in the outgoing request along
+    /// with a `Stream` of the packets (or the connection failure) that
+    /// come back on it.
+    pub fn open(&mut self) -> (i32, mpsc::Receiver<Result<Packet, Arc<Error>>>) {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let (sender, receiver) = mpsc::channel(CHANNEL_BUFFER);
+        self.channels.insert(-id, sender);
+        (id, receiver)
+    }
+
+    /// Drive the underlying `PacketStream` to completion, routing each
+    /// packet to the channel matching its id and closing that channel once
+    /// a packet with the end/error flag set arrives on it. If the
+    /// underlying stream errors, or ends without every open channel having
+    /// been closed first, every channel still open is sent the failure (or
+    /// [`Error::Gone`] for a clean-but-premature goodbye) so that no
+    /// caller mistakes a dead connection for a closed request. Intended to
+    /// be spawned onto an executor and run for the lifetime of the
+    /// connection.
+    pub async fn run(mut self) -> Result<(), Arc<Error>> {
+        loop {
+            match self.inner.next().await {
+                None => {
+                    self.fail_all(Arc::new(Error::Gone {})).await;
+                    return Ok(());
+                }
+                Some(Err(e)) => {
+                    let e = Arc::new(Error::Stream { source: e });
+                    self.fail_all(e.clone()).await;
+                    return Err(e);
+                }
+                Some(Ok(packet)) => {
+                    let id = packet.id;
+                    let is_end = packet.is_end == IsEnd::Yes;
+
+                    if let Some(sender) = self.channels.get_mut(&id) {
+                        if sender.send(Ok(packet)).await.is_err() || is_end {
+                            self.channels.remove(&id);
+                        }
+                    }
+                    // A packet for an id nobody opened a channel for is dropped.
+                }
+            }
+        }
+    }
+
+    /// Deliver `e` to every channel still open. Uses the backpressure-aware
+    /// `send`, not `try_send`, so a channel that is already full
+    /// still gets the terminal error once its receiver makes
+    /// room, rather than having it silently dropped.
+    async fn fail_all(&mut self, e: Arc<Error>) {
+        for (_, mut sender) in self.channels.drain() {
+            let _ = sender.send(Err(e.clone())).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::packet_bytes;
+    use futures::executor::block_on;
+    use futures::io::AllowStdIo;
+    use std::io::Cursor;
+
+    #[test]
+    fn routes_packets_by_id_and_closes_the_channel_on_end() {
+        let mut bytes = packet_bytes(true, false, -1, b"first");
+        bytes.extend(packet_bytes(true, true, -1, b"last"));
+        bytes.extend(packet_bytes(true, false, -2, b"for nobody"));
+        bytes.extend(vec![0u8; 9]); // goodbye
+
+        let mut demux = Demux::new(AllowStdIo::new(Cursor::new(bytes)));
+        let (id, mut receiver) = demux.open();
+        assert_eq!(id, 1);
+
+        block_on(demux.run()).unwrap();
+
+        let first = block_on(receiver.next()).unwrap().unwrap();
+        assert_eq!(&first.body[..], b"first");
+
+        let last = block_on(receiver.next()).unwrap().unwrap();
+        assert_eq!(&last.body[..], b"last");
+
+        // The channel was closed once its end packet arrived, so no
+        // failure is reported even though the connection later went away.
+        assert_eq!(block_on(receiver.next()), None);
+    }
+
+    #[test]
+    fn delivers_the_failure_even_to_a_channel_that_is_full() {
+        // Build a sender/receiver pair by hand and fill it past capacity,
+        // bypassing `open()`, so the channel is already full by the time
+        // the connection fails.
+        let (mut sender, mut receiver) = mpsc::channel(CHANNEL_BUFFER);
+        let mut filled = 0;
+        while sender
+            .try_send(Ok(Packet::new(
+                IsStream::Yes,
+                IsEnd::No,
+                BodyType::Binary,
+                1,
+                Vec::new().into(),
+            )))
+            .is_ok()
+        {
+            filled += 1;
+        }
+        assert!(filled > 0);
+
+        let demux = Demux {
+            inner: PacketStream::new(AllowStdIo::new(Cursor::new(vec![0u8; 9]))), // goodbye
+            channels: vec![(1, sender)].into_iter().collect(),
+            next_id: 2,
+        };
+
+        block_on(async {
+            let run = demux.run();
+            let drain = async {
+                for _ in 0..filled {
+                    receiver.next().await.unwrap().unwrap();
+                }
+                receiver.next().await
+            };
+            let (run_result, last) = futures::join!(run, drain);
+
+            run_result.unwrap();
+            match last {
+                Some(Err(_)) => {}
+                other => panic!("expected the connection failure, got {:?}", other),
+            }
+        });
+    }
+}
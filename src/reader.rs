@@ -0,0 +1,154 @@
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use futures::io::AsyncRead;
+use futures::stream::Stream;
+
+use crate::packet::*;
+
+/// Adapts a fallible stream of same-id [`Packet`]s — e.g. the receiver
+/// handed back by [`crate::demux::Demux::open`], or a
+/// [`crate::stream::PacketStream`] already filtered down to one id — into
+/// a contiguous [`AsyncRead`], so a payload delivered as a sequence of
+/// `IsStream::Yes` chunks can be read like any other byte stream instead of
+/// collected and concatenated by hand. A stream error is surfaced from
+/// `poll_read` as an `io::Error`, same as an `IsEnd`-flagged error body.
+pub struct PacketBodyReader<S> {
+    inner: S,
+    buffered: VecDeque<Bytes>,
+    cursor: usize,
+    done: bool,
+}
+
+impl<S> PacketBodyReader<S> {
+    pub fn new(inner: S) -> PacketBodyReader<S> {
+        PacketBodyReader {
+            inner,
+            buffered: VecDeque::new(),
+            cursor: 0,
+            done: false,
+        }
+    }
+}
+
+impl<S, E> AsyncRead for PacketBodyReader<S>
+where
+    S: Stream<Item = Result<Packet, E>> + Unpin,
+    E: Display,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            if let Some(front) = self.buffered.front() {
+                if self.cursor < front.len() {
+                    let n = (front.len() - self.cursor).min(buf.len());
+                    buf[..n].copy_from_slice(&front[self.cursor..self.cursor + n]);
+                    self.cursor += n;
+                    return Poll::Ready(Ok(n));
+                }
+                self.buffered.pop_front();
+                self.cursor = 0;
+                continue;
+            }
+
+            if self.done {
+                return Poll::Ready(Ok(0));
+            }
+
+            match Pin::new(&mut self.inner).poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => {
+                    self.done = true;
+                }
+                Poll::Ready(Some(Err(e))) => {
+                    self.done = true;
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, e.to_string())));
+                }
+                Poll::Ready(Some(Ok(packet))) => {
+                    self.done = packet.is_end == IsEnd::Yes;
+                    if self.done && !packet.body.is_empty() {
+                        return Poll::Ready(Err(io::Error::new(
+                            io::ErrorKind::Other,
+                            String::from_utf8_lossy(&packet.body).into_owned(),
+                        )));
+                    }
+                    if !packet.body.is_empty() {
+                        self.buffered.push_back(packet.body);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demux::Demux;
+    use crate::test_helpers::packet_bytes;
+    use futures::executor::block_on;
+    use futures::io::{AllowStdIo, AsyncReadExt};
+    use futures::stream::iter;
+    use std::convert::Infallible;
+    use std::io::Cursor;
+
+    #[test]
+    fn concatenates_a_multi_packet_body_across_reads() {
+        let packets: Vec<Result<Packet, Infallible>> = vec![
+            Ok(Packet::new(IsStream::Yes, IsEnd::No, BodyType::Binary, 1, b"hello, "[..].into())),
+            Ok(Packet::new(IsStream::Yes, IsEnd::No, BodyType::Binary, 1, b"world"[..].into())),
+            Ok(Packet::new(IsStream::Yes, IsEnd::Yes, BodyType::Binary, 1, Bytes::new())),
+        ];
+        let mut reader = PacketBodyReader::new(iter(packets));
+
+        let mut out = Vec::new();
+        block_on(reader.read_to_end(&mut out)).unwrap();
+
+        assert_eq!(&out, b"hello, world");
+    }
+
+    #[test]
+    fn surfaces_a_non_empty_end_packet_as_an_io_error() {
+        let packets: Vec<Result<Packet, Infallible>> = vec![Ok(Packet::new(
+            IsStream::Yes,
+            IsEnd::Yes,
+            BodyType::Json,
+            1,
+            b"{\"error\":\"nope\"}"[..].into(),
+        ))];
+        let mut reader = PacketBodyReader::new(iter(packets));
+
+        let mut out = Vec::new();
+        let err = block_on(reader.read_to_end(&mut out)).unwrap_err();
+        assert_eq!(err.to_string(), "{\"error\":\"nope\"}");
+    }
+
+    #[test]
+    fn surfaces_a_demux_connection_failure_as_an_io_error() {
+        // A goodbye but no end packet for id -1, so `Demux::run` sees the
+        // stream end while a channel is still open and fails it with
+        // `demux::Error::Gone`.
+        let mut bytes = packet_bytes(true, false, -1, b"partial");
+        bytes.extend(vec![0u8; 9]); // goodbye
+
+        let mut demux = Demux::new(AllowStdIo::new(Cursor::new(bytes)));
+        let (id, receiver) = demux.open();
+        assert_eq!(id, 1);
+        let mut reader = PacketBodyReader::new(receiver);
+
+        let (run_result, read_result) = block_on(async {
+            futures::join!(demux.run(), reader.read_to_end(&mut Vec::new()))
+        });
+
+        assert!(run_result.is_ok());
+        let err = read_result.unwrap_err();
+        assert!(err.to_string().contains("PacketStream ended"));
+    }
+}
@@ -1,14 +1,31 @@
 use byteorder::{BigEndian, ByteOrder};
+use bytes::BytesMut;
 use core::pin::Pin;
 use core::task::{Context, Poll, Poll::Pending, Poll::Ready};
 use futures::io::{AsyncRead, AsyncReadExt};
 use futures::stream::{FusedStream, Stream};
 use std::mem::replace;
+use std::time::{Duration, Instant};
 
 use crate::packet::*;
 use crate::PinFut;
 use snafu::{ensure, ResultExt, Snafu};
 
+/// Default cap on the advertised body length of a single packet, used by
+/// [`PacketStream::new`]. Pick a tighter limit with
+/// [`PacketStream::with_max_body_len`] if the protocol on top of this stream
+/// doesn't need bodies this large.
+pub const DEFAULT_MAX_BODY_LEN: usize = 8 * 1024 * 1024;
+
+/// How often [`PacketStream::download_rate`] is refreshed.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// The body is grown and read in chunks of this size rather than all at
+/// once, so that a packet advertising a large (but still under
+/// `max_body_len`) length doesn't force the full buffer to be allocated
+/// and zeroed before a single body byte has actually arrived.
+const READ_CHUNK_LEN: usize = 64 * 1024;
+
 #[derive(Debug, Snafu)]
 pub enum Error {
     #[snafu(display("Failed to receive packet: {}", source))]
@@ -26,9 +43,20 @@ pub enum Error {
 
     #[snafu(display("PacketStream underlying reader closed without goodbye"))]
     NoGoodbye {},
+
+    #[snafu(display(
+        "Packet body size {} exceeds the maximum allowed size of {}",
+        size,
+        max
+    ))]
+    TooLarge { size: usize, max: usize },
 }
 
-async fn recv<R>(r: &mut R) -> Result<Option<Packet>, Error>
+async fn recv<R>(
+    r: &mut R,
+    max_body_len: usize,
+    buf: &mut BytesMut,
+) -> Result<Option<Packet>, Error>
 where
     R: AsyncRead + Unpin,
 {
@@ -46,10 +74,34 @@ where
     let body_len = BigEndian::read_u32(&head[1..5]) as usize;
     let id = BigEndian::read_i32(&head[5..]);
 
-    let mut body = vec![0; body_len];
-    r.read_exact(&mut body)
-        .await
-        .context(Body { size: body_len })?;
+    ensure!(
+        body_len <= max_body_len,
+        TooLarge {
+            size: body_len,
+            max: max_body_len
+        }
+    );
+
+    // `buf` is owned by the PacketStream and carried across calls, so a
+    // caller that has already dropped the previous packet's `Bytes` gets
+    // its allocation reused here instead of paying for a fresh one. If
+    // that `Bytes` is still held (e.g. queued in a Demux channel or a
+    // PacketBodyReader), `BytesMut` has to allocate fresh storage instead
+    // of writing in place - reuse is an opportunistic win, not a
+    // guarantee. Still grow and fill the buffer in bounded chunks rather
+    // than all at once, so a packet that advertises a large body and then
+    // stalls or trickles doesn't force the whole (still-capped) buffer to
+    // be allocated and zeroed up front.
+    let mut received = 0;
+    while received < body_len {
+        let new_len = buf.len() + READ_CHUNK_LEN.min(body_len - received);
+        buf.resize(new_len, 0);
+        r.read_exact(&mut buf[received..new_len])
+            .await
+            .context(Body { size: body_len })?;
+        received = new_len;
+    }
+    let body = buf.split_to(body_len).freeze();
 
     Ok(Some(Packet::new(
         head[0].into(),
@@ -60,12 +112,16 @@ where
     )))
 }
 
-async fn recv_move<R>(mut r: R) -> (R, Result<Option<Packet>, Error>)
+async fn recv_move<R>(
+    mut r: R,
+    max_body_len: usize,
+    mut buf: BytesMut,
+) -> (R, BytesMut, Result<Option<Packet>, Error>)
 where
     R: AsyncRead + Unpin + 'static,
 {
-    let res = recv(&mut r).await;
-    (r, res)
+    let res = recv(&mut r, max_body_len, &mut buf).await;
+    (r, buf, res)
 }
 
 /// # Examples
@@ -94,33 +150,96 @@ where
 /// ```
 pub struct PacketStream<R> {
     state: State<R>,
+    max_body_len: usize,
+    packets_received: u64,
+    bytes_received: u64,
+    window_start: Instant,
+    window_bytes: u64,
+    download_rate: u64,
 }
 impl<R> PacketStream<R> {
     pub fn new(r: R) -> PacketStream<R> {
+        PacketStream::with_max_body_len(r, DEFAULT_MAX_BODY_LEN)
+    }
+
+    /// Like [`PacketStream::new`], but rejects any packet whose advertised
+    /// body length is greater than `max_body_len` with
+    /// [`Error::TooLarge`], before allocating a buffer for it.
+    pub fn with_max_body_len(r: R, max_body_len: usize) -> PacketStream<R> {
         PacketStream {
-            state: State::Ready(r),
+            state: State::Ready(r, BytesMut::new()),
+            max_body_len,
+            packets_received: 0,
+            bytes_received: 0,
+            window_start: Instant::now(),
+            window_bytes: 0,
+            download_rate: 0,
         }
     }
 
     pub fn is_closed(&self) -> bool {
         match &self.state {
-            State::Closed(_) => true,
+            State::Closed(..) => true,
             _ => false,
         }
     }
 
     pub fn into_inner(mut self) -> R {
         match self.state.take() {
-            State::Ready(r) | State::Closed(r) => r,
+            State::Ready(r, _) | State::Closed(r, _) => r,
             _ => panic!(),
         }
     }
+
+    /// Total number of packets received so far.
+    pub fn packets_received(&self) -> u64 {
+        self.packets_received
+    }
+
+    /// Total number of body bytes received so far.
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received
+    }
+
+    /// Estimated download rate, in bytes/sec, based on the bytes received
+    /// during the most recently completed measurement window. If the
+    /// window is already stale (no packet has arrived to roll it over),
+    /// this is computed live from however much of the window has elapsed,
+    /// so an idle connection reports a falling rate instead of holding on
+    /// to whatever the last completed window saw.
+    pub fn download_rate(&self) -> u64 {
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= RATE_WINDOW {
+            bytes_per_sec(self.window_bytes, elapsed)
+        } else {
+            self.download_rate
+        }
+    }
+
+    fn record_packet(&mut self, body_len: usize) {
+        self.packets_received += 1;
+        self.bytes_received += body_len as u64;
+        self.window_bytes += body_len as u64;
+
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= RATE_WINDOW {
+            self.download_rate = bytes_per_sec(self.window_bytes, elapsed);
+            self.window_bytes = 0;
+            self.window_start = Instant::now();
+        }
+    }
+}
+
+/// Normalize a byte count accumulated over `elapsed` into a bytes/sec
+/// rate, rather than assuming exactly one measurement window passed.
+fn bytes_per_sec(bytes: u64, elapsed: Duration) -> u64 {
+    (bytes as f64 / elapsed.as_secs_f64()).round() as u64
 }
 
 enum State<R> {
-    Ready(R),
-    Waiting(PinFut<(R, Result<Option<Packet>, Error>)>),
-    Closed(R),
+    Ready(R, BytesMut),
+    Waiting(PinFut<(R, BytesMut, Result<Option<Packet>, Error>)>),
+    Closed(R, BytesMut),
     Invalid,
 }
 impl<R> State<R> {
@@ -129,19 +248,27 @@ impl<R> State<R> {
     }
 }
 
-fn next<R>(state: State<R>, cx: &mut Context) -> (State<R>, Poll<Option<Result<Packet, Error>>>)
+fn next<R>(
+    state: State<R>,
+    max_body_len: usize,
+    cx: &mut Context,
+) -> (State<R>, Poll<Option<Result<Packet, Error>>>)
 where
     R: AsyncRead + Unpin + 'static,
 {
     match state {
-        State::Ready(r) => next(State::Waiting(Box::pin(recv_move(r))), cx),
+        State::Ready(r, buf) => next(
+            State::Waiting(Box::pin(recv_move(r, max_body_len, buf))),
+            max_body_len,
+            cx,
+        ),
         State::Waiting(mut f) => match f.as_mut().poll(cx) {
             Pending => (State::Waiting(f), Pending),
-            Ready((r, Ok(None))) => (State::Closed(r), Ready(None)),
-            Ready((r, Err(e))) => (State::Closed(r), Ready(Some(Err(e)))),
-            Ready((r, res)) => (State::Ready(r), Ready(res.transpose())),
+            Ready((r, buf, Ok(None))) => (State::Closed(r, buf), Ready(None)),
+            Ready((r, buf, Err(e))) => (State::Closed(r, buf), Ready(Some(Err(e)))),
+            Ready((r, buf, res)) => (State::Ready(r, buf), Ready(res.transpose())),
         },
-        State::Closed(r) => (State::Closed(r), Ready(None)),
+        State::Closed(r, buf) => (State::Closed(r, buf), Ready(None)),
         State::Invalid => panic!(),
     }
 }
@@ -150,8 +277,11 @@ impl<R: AsyncRead + Unpin + 'static> Stream for PacketStream<R> {
     type Item = Result<Packet, Error>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-        let (state, poll) = next(self.state.take(), cx);
+        let (state, poll) = next(self.state.take(), self.max_body_len, cx);
         self.state = state;
+        if let Ready(Some(Ok(packet))) = &poll {
+            self.record_packet(packet.body.len());
+        }
         poll
     }
 }
@@ -161,3 +291,127 @@ impl<R: AsyncRead + Unpin + 'static> FusedStream for PacketStream<R> {
         self.is_closed()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::packet_bytes;
+    use futures::executor::block_on;
+    use futures::io::AllowStdIo;
+    use futures::stream::StreamExt;
+    use std::io::Cursor;
+
+    #[test]
+    fn does_not_reuse_the_buffer_while_the_previous_body_is_still_held() {
+        let mut bytes = packet_bytes(true, false, 1, b"aaaa");
+        bytes.extend(packet_bytes(true, false, 2, b"bbbb"));
+        let mut stream = PacketStream::new(AllowStdIo::new(Cursor::new(bytes)));
+
+        let first = block_on(stream.next()).unwrap().unwrap();
+        // Holding on to `first.body` keeps the scratch buffer's backing
+        // allocation shared, so per `bytes`' own rules the next body has
+        // to land in a fresh allocation rather than reusing it in place.
+        let second = block_on(stream.next()).unwrap().unwrap();
+
+        assert_ne!(first.body.as_ptr(), second.body.as_ptr());
+        assert_eq!(&first.body[..], b"aaaa");
+        assert_eq!(&second.body[..], b"bbbb");
+    }
+
+    #[test]
+    fn receives_a_normal_sized_packet() {
+        let bytes = packet_bytes(true, false, 12345, b"hello");
+        let mut stream = PacketStream::new(AllowStdIo::new(Cursor::new(bytes)));
+
+        let packet = block_on(stream.next()).unwrap().unwrap();
+
+        assert_eq!(&packet.body[..], b"hello");
+        assert_eq!(packet.id, 12345);
+    }
+
+    #[test]
+    fn rejects_a_body_larger_than_the_configured_max_before_reading_it() {
+        // A header advertising a 1 KiB body, but no body bytes behind it:
+        // if `recv` tried to read the body before checking the length, this
+        // would fail with `Error::Body` (unexpected EOF) instead.
+        let mut header = vec![0u8; 9];
+        header[0] = 1; // stream, not end
+        BigEndian::write_u32(&mut header[1..5], 1024);
+        BigEndian::write_i32(&mut header[5..9], 1);
+
+        let mut stream = PacketStream::with_max_body_len(AllowStdIo::new(Cursor::new(header)), 16);
+
+        match block_on(stream.next()) {
+            Some(Err(Error::TooLarge { size, max })) => {
+                assert_eq!(size, 1024);
+                assert_eq!(max, 16);
+            }
+            other => panic!("expected Error::TooLarge, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn tracks_packets_and_bytes_received() {
+        let mut bytes = packet_bytes(true, false, 1, b"aaaa");
+        bytes.extend(packet_bytes(true, false, 2, b"bb"));
+        let mut stream = PacketStream::new(AllowStdIo::new(Cursor::new(bytes)));
+
+        assert_eq!(stream.packets_received(), 0);
+        assert_eq!(stream.bytes_received(), 0);
+
+        block_on(stream.next()).unwrap().unwrap();
+        assert_eq!(stream.packets_received(), 1);
+        assert_eq!(stream.bytes_received(), 4);
+
+        block_on(stream.next()).unwrap().unwrap();
+        assert_eq!(stream.packets_received(), 2);
+        assert_eq!(stream.bytes_received(), 6);
+
+        // The measurement window (1s) hasn't elapsed yet, so the rolling
+        // rate hasn't rolled over from its initial value.
+        assert_eq!(stream.download_rate(), 0);
+    }
+
+    #[test]
+    fn normalizes_the_rate_by_how_long_the_window_actually_took() {
+        let mut bytes = packet_bytes(true, false, 1, &vec![0u8; 2_000_000]);
+        bytes.extend(packet_bytes(true, false, 1, b"x"));
+        let mut stream = PacketStream::new(AllowStdIo::new(Cursor::new(bytes)));
+
+        block_on(stream.next()).unwrap().unwrap();
+        assert_eq!(stream.download_rate(), 0);
+
+        std::thread::sleep(Duration::from_millis(1100));
+
+        // This packet arrives well after the 2,000,000 bytes did, so a
+        // rate that just reported the raw byte count (as if exactly one
+        // window had elapsed) would overstate the actual throughput.
+        block_on(stream.next()).unwrap().unwrap();
+        let rate = stream.download_rate();
+        assert!(
+            rate > 1_000_000 && rate < 2_000_000,
+            "expected a rate normalized by elapsed time, got {}",
+            rate
+        );
+    }
+
+    #[test]
+    fn download_rate_reflects_idle_time_without_a_new_packet() {
+        let bytes = packet_bytes(true, false, 1, &vec![0u8; 1000]);
+        let mut stream = PacketStream::new(AllowStdIo::new(Cursor::new(bytes)));
+        block_on(stream.next()).unwrap().unwrap();
+
+        std::thread::sleep(Duration::from_millis(1100));
+
+        // No further packet has arrived to roll the window over, but it's
+        // long since stale; the rate shouldn't keep reporting whatever the
+        // last completed window saw (0 here). It is computed live instead:
+        // 1000 bytes over at least 1.1s is at most 909 bytes/sec.
+        let rate = stream.download_rate();
+        assert!(
+            rate > 0 && rate <= 909,
+            "expected a live rate from the stale window, got {}",
+            rate
+        );
+    }
+}
@@ -0,0 +1,16 @@
+#![cfg(test)]
+
+use byteorder::{BigEndian, ByteOrder};
+
+/// Build the wire bytes for a single packet: the 9-byte header
+/// (flags, body length, id) that `stream::recv` parses, followed by
+/// the body itself. Shared by `stream`, `demux` and `reader`'s tests so the wire
+/// format only has to be encoded by hand in one place.
+pub(crate) fn packet_bytes(is_stream: bool, is_end: bool, id: i32, body: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![0u8; 9 + body.len()];
+    bytes[0] = (is_stream as u8) | ((is_end as u8) << 1);
+    BigEndian::write_u32(&mut bytes[1..5], body.len() as u32);
+    BigEndian::write_i32(&mut bytes[5..9], id);
+    bytes[9..].copy_from_slice(body);
+    bytes
+}